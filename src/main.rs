@@ -2,9 +2,15 @@
 extern crate rocket;
 
 use chrono::Utc;
-use rocket::tokio::{
-    self,
-    time::{self, Duration},
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::{
+        self,
+        sync::{Mutex as AsyncMutex, RwLock},
+        task::JoinHandle,
+        time::{self, Duration},
+    },
+    Orbit, Rocket, Shutdown,
 };
 use rppal::pwm::Pwm;
 use std::sync::Arc;
@@ -16,32 +22,25 @@ pub mod config {
         form::{self, FromForm, FromFormField},
         http::Status,
         serde::{Deserialize, Serialize},
-        tokio::fs,
+        tokio::{fs, sync::RwLock},
     };
+    use std::sync::Arc;
 
     const CONFIG_FILE: &str = "config.toml";
+    const CONFIG_TMP_FILE: &str = "config.toml.tmp";
+    const MAX_SWEEP_MS: u64 = 60_000;
 
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct FormDateTime {
-        #[serde(with = "ts_seconds")]
-        pub inner: DateTime<Utc>,
-    }
+    pub type ConfigState = Arc<RwLock<Config>>;
 
-    impl<'r> FromFormField<'r> for FormDateTime {
-        fn from_value(field: form::ValueField<'r>) -> form::Result<'r, Self> {
-            Ok(FormDateTime {
-                inner: match Utc.timestamp_opt(field.value.parse::<i64>()?, 0) {
-                    LocalResult::Single(datetime) => datetime,
-                    LocalResult::None => {
-                        return Err(form::Error::validation("invalid timestamp").into())
-                    }
-                    LocalResult::Ambiguous(_, _) => unreachable!(),
-                },
-            })
+    fn parse_timestamp<'v>(value: &str) -> form::Result<'v, DateTime<Utc>> {
+        match Utc.timestamp_opt(value.parse::<i64>()?, 0) {
+            LocalResult::Single(datetime) => Ok(datetime),
+            LocalResult::None => Err(form::Error::validation("invalid timestamp").into()),
+            LocalResult::Ambiguous(_, _) => unreachable!(),
         }
     }
 
-    #[derive(Debug, Serialize, Deserialize, FromFormField)]
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, FromFormField)]
     pub enum LightState {
         On,
         Off,
@@ -56,29 +55,224 @@ pub mod config {
         }
     }
 
-    #[derive(Debug, Serialize, Deserialize, FromForm)]
+    #[derive(Debug, Clone, Serialize, Deserialize, FromForm)]
     pub struct FlipSettings {
         pub delay: u64, // ms
         #[field(validate = servo_value_validate())]
         pub servo_value: f64,
+        /// `None` jumps the pulse width directly to the target, as before.
+        #[field(validate = sweep_ms_validate())]
+        pub sweep_ms: Option<u64>,
+    }
+
+    /// Parsed from a single form field: `once:<unix ts>`,
+    /// `every:<unix ts base>:<period secs>` or
+    /// `daily:<secs since midnight UTC>:<weekday bitmask, bit0 = Monday>`.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub enum Recurrence {
+        Once(#[serde(with = "ts_seconds")] DateTime<Utc>),
+        EveryInterval {
+            #[serde(with = "ts_seconds")]
+            base: DateTime<Utc>,
+            period_secs: u64,
+        },
+        Daily {
+            time_of_day_secs: u32,
+            weekdays: u8,
+        },
+    }
+
+    impl Recurrence {
+        pub fn initial_fire(&self) -> DateTime<Utc> {
+            match *self {
+                Self::Once(datetime) => datetime,
+                Self::EveryInterval { base, .. } => base,
+                Self::Daily { .. } => self.next_after(Utc::now()),
+            }
+        }
+
+        /// `None` means the schedule shouldn't recur (a `Once` that already fired).
+        pub fn advance(&self, next_fire: DateTime<Utc>) -> Option<DateTime<Utc>> {
+            match *self {
+                Self::Once(_) => None,
+                Self::EveryInterval { period_secs, .. } => {
+                    let period = chrono::Duration::seconds(period_secs.max(1) as i64);
+                    let mut next_fire = next_fire;
+                    while next_fire <= Utc::now() {
+                        next_fire += period;
+                    }
+                    Some(next_fire)
+                }
+                Self::Daily { .. } => {
+                    let mut next_fire = next_fire;
+                    while next_fire <= Utc::now() {
+                        next_fire = self.next_after(next_fire);
+                    }
+                    Some(next_fire)
+                }
+            }
+        }
+
+        /// The next `Daily` fire strictly after `after`, honoring `weekdays`.
+        fn next_after(&self, after: DateTime<Utc>) -> DateTime<Utc> {
+            use chrono::Datelike;
+
+            let Self::Daily {
+                time_of_day_secs,
+                weekdays,
+            } = *self
+            else {
+                unreachable!("next_after is only called for Daily recurrences")
+            };
+
+            let mut day = after.date_naive();
+            loop {
+                let candidate = day
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
+                    .and_utc()
+                    .checked_add_signed(chrono::Duration::seconds(time_of_day_secs as i64))
+                    .unwrap();
+                let weekday_bit = 1 << day.weekday().num_days_from_monday();
+                if candidate > after && weekday_bit & weekdays != 0 {
+                    return candidate;
+                }
+                day += chrono::Duration::days(1);
+            }
+        }
+    }
+
+    impl<'r> FromFormField<'r> for Recurrence {
+        fn from_value(field: form::ValueField<'r>) -> form::Result<'r, Self> {
+            let mut parts = field.value.splitn(3, ':');
+            let kind = parts.next().unwrap_or_default();
+            match (kind, parts.next(), parts.next()) {
+                ("once", Some(ts), None) => Ok(Self::Once(parse_timestamp(ts)?)),
+                ("every", Some(base), Some(period_secs)) => Ok(Self::EveryInterval {
+                    base: parse_timestamp(base)?,
+                    period_secs: period_secs
+                        .parse()
+                        .map_err(|_| form::Error::validation("invalid period_secs"))?,
+                }),
+                ("daily", Some(time_of_day_secs), Some(weekdays)) => {
+                    let weekdays: u8 = weekdays
+                        .parse()
+                        .map_err(|_| form::Error::validation("invalid weekdays bitmask"))?;
+                    if weekdays == 0 || weekdays > 0x7F {
+                        return Err(form::Error::validation(
+                            "weekdays must have at least one of the low 7 bits set",
+                        )
+                        .into());
+                    }
+                    Ok(Self::Daily {
+                        time_of_day_secs: time_of_day_secs
+                            .parse()
+                            .map_err(|_| form::Error::validation("invalid time_of_day_secs"))?,
+                        weekdays,
+                    })
+                }
+                _ => Err(form::Error::validation(
+                    "expected once:<ts>, every:<base>:<period_secs> or daily:<secs>:<weekdays>",
+                )
+                .into()),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn daily_advance_catches_up_in_one_step() {
+            let recurrence = Recurrence::Daily {
+                time_of_day_secs: 0,
+                weekdays: 0x7F,
+            };
+            let stale = Utc::now() - chrono::Duration::days(3);
+
+            let next_fire = recurrence.advance(stale).unwrap();
+
+            assert!(next_fire > Utc::now());
+        }
+
+        #[test]
+        fn every_interval_advance_catches_up_in_one_step() {
+            let recurrence = Recurrence::EveryInterval {
+                base: Utc::now() - chrono::Duration::days(3),
+                period_secs: 3600,
+            };
+            let stale = Utc::now() - chrono::Duration::days(3);
+
+            let next_fire = recurrence.advance(stale).unwrap();
+
+            assert!(next_fire > Utc::now());
+        }
+
+        #[test]
+        fn once_does_not_recur() {
+            let recurrence = Recurrence::Once(Utc::now());
+            assert!(recurrence.advance(Utc::now()).is_none());
+        }
+
+        #[test]
+        fn daily_initial_fire_is_in_the_future() {
+            for time_of_day_secs in [0, 60, 12 * 3600, 23 * 3600 + 3599] {
+                let recurrence = Recurrence::Daily {
+                    time_of_day_secs,
+                    weekdays: 0x7F,
+                };
+                assert!(recurrence.initial_fire() > Utc::now());
+            }
+        }
     }
 
+    /// `id` is omitted to create a new entry, or provided to replace an existing one.
     #[derive(Debug, Serialize, Deserialize, FromForm)]
-    pub struct ScheduledFlip {
+    pub struct ScheduleRequest {
+        pub id: Option<String>,
+        pub state: LightState,
+        pub recurrence: Recurrence,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Schedule {
+        pub id: String,
         pub state: LightState,
-        #[field(validate = formdatetime_validate())]
-        pub datetime: FormDateTime,
+        pub recurrence: Recurrence,
+        #[serde(with = "ts_seconds")]
+        pub next_fire: DateTime<Utc>,
+    }
+
+    pub fn generate_schedule_id() -> String {
+        format!("{:016x}", rand::thread_rng().next_u64())
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Config {
         pub idle_servo_value: f64,
-        pub api_key: String,
-        pub scheduled_flip: Option<ScheduledFlip>,
+        pub api_keys: Vec<ApiKeyEntry>,
+        pub schedules: Vec<Schedule>,
         pub on_settings: FlipSettings,
         pub off_settings: FlipSettings,
+        /// Seconds Rocket waits for the shutdown cleanup sweep to finish.
+        pub shutdown_grace_secs: u32,
+        /// `None` retries a failed scheduled flip forever instead of giving up.
+        pub max_errors_in_row: Option<usize>,
+        /// Doubles on each retry of a failed scheduled flip, capped at 60s.
+        pub retry_base_delay_ms: u64,
+    }
+
+    /// Not persisted to disk; resets on restart.
+    #[derive(Debug, Default, Clone)]
+    pub struct HealthStatus {
+        pub last_success: Option<DateTime<Utc>>,
+        pub consecutive_failures: usize,
+        pub last_error: Option<String>,
     }
 
+    pub type HealthState = Arc<RwLock<HealthStatus>>;
+
     fn servo_value_validate<'v>(servo_value: &f64) -> form::Result<'v, ()> {
         if *servo_value < -1.0 || *servo_value > 1.0 {
             return Err(
@@ -88,26 +282,73 @@ pub mod config {
         Ok(())
     }
 
-    fn formdatetime_validate<'v>(formdatetime: &FormDateTime) -> form::Result<'v, ()> {
-        if formdatetime.inner <= Utc::now() {
-            return Err(form::Error::validation("invalid datetime, must be in the future").into());
+    fn sweep_ms_validate<'v>(sweep_ms: &Option<u64>) -> form::Result<'v, ()> {
+        if sweep_ms.is_some_and(|sweep_ms| sweep_ms > MAX_SWEEP_MS) {
+            return Err(form::Error::validation(format!(
+                "invalid sweep_ms, must be at most {MAX_SWEEP_MS}"
+            ))
+            .into());
         }
         Ok(())
     }
 
-    fn generate_api_key() -> String {
+    pub(crate) fn generate_api_key() -> String {
         let mut data = [0u8; 32];
         rand::thread_rng().fill_bytes(&mut data);
         base64::encode(data)
     }
 
+    /// `ReadOnly` keys may only hit `GET` routes; `Full` keys may also mutate state.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+    pub enum ApiKeyScope {
+        ReadOnly,
+        Full,
+    }
+
+    /// `not_after` is `None` for a key that never expires.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ApiKeyEntry {
+        pub secret: String,
+        pub label: Option<String>,
+        pub scope: ApiKeyScope,
+        #[serde(with = "chrono::serde::ts_seconds_option")]
+        pub not_after: Option<DateTime<Utc>>,
+    }
+
+    impl ApiKeyEntry {
+        pub fn is_expired(&self) -> bool {
+            self.not_after.is_some_and(|not_after| not_after <= Utc::now())
+        }
+    }
+
+    /// A single form-encoded unix timestamp, for fields where [`Recurrence`]'s
+    /// richer encoding isn't needed.
+    #[derive(Debug, Clone, Copy)]
+    pub struct FormTimestamp(pub DateTime<Utc>);
+
+    impl<'r> FromFormField<'r> for FormTimestamp {
+        fn from_value(field: form::ValueField<'r>) -> form::Result<'r, Self> {
+            Ok(FormTimestamp(parse_timestamp(field.value)?))
+        }
+    }
+
+    #[derive(Debug, FromForm)]
+    pub struct MintApiKeyRequest {
+        pub label: Option<String>,
+        pub scope: ApiKeyScope,
+        pub not_after: Option<FormTimestamp>,
+    }
+
+    /// Writes via a temp file and rename so readers never see a half-written file.
     pub async fn write_config_file(config: &Config) -> Result<(), (Status, String)> {
-        fs::write(
-            CONFIG_FILE,
-            toml::to_string(&config).map_err(|e| (Status::InternalServerError, e.to_string()))?,
-        )
-        .await
-        .map_err(|e| (Status::InternalServerError, e.to_string()))
+        let serialized =
+            toml::to_string(&config).map_err(|e| (Status::InternalServerError, e.to_string()))?;
+        fs::write(CONFIG_TMP_FILE, serialized)
+            .await
+            .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+        fs::rename(CONFIG_TMP_FILE, CONFIG_FILE)
+            .await
+            .map_err(|e| (Status::InternalServerError, e.to_string()))
     }
 
     pub async fn read_config_file() -> Result<Config, (Status, String)> {
@@ -116,16 +357,26 @@ pub mod config {
             Err(_) => {
                 let config = Config {
                     idle_servo_value: 0.0,
-                    scheduled_flip: None,
+                    schedules: Vec::new(),
                     on_settings: FlipSettings {
                         delay: 0,
                         servo_value: 0.0,
+                        sweep_ms: None,
                     },
                     off_settings: FlipSettings {
                         delay: 0,
                         servo_value: 0.0,
+                        sweep_ms: None,
                     },
-                    api_key: generate_api_key(),
+                    api_keys: vec![ApiKeyEntry {
+                        secret: generate_api_key(),
+                        label: Some("default".to_string()),
+                        scope: ApiKeyScope::Full,
+                        not_after: None,
+                    }],
+                    shutdown_grace_secs: 2,
+                    max_errors_in_row: Some(5),
+                    retry_base_delay_ms: 1000,
                 };
                 write_config_file(&config).await.and(Ok(config))
             }
@@ -179,6 +430,36 @@ pub mod servo {
             .map_err(to_500)
     }
 
+    /// Linearly interpolated in microseconds, one `PERIOD` tick at a time.
+    /// Clamped to at least one step.
+    async fn sweep(from: f64, to: f64, sweep_ms: u64, pwm: &Pwm) -> Result<(), (Status, String)> {
+        let steps = (sweep_ms / PERIOD.as_millis() as u64).max(1);
+        let from_us = calc_pulse_width(from).as_micros() as i64;
+        let to_us = calc_pulse_width(to).as_micros() as i64;
+
+        for step in 1..=steps {
+            let us = from_us + (to_us - from_us) * step as i64 / steps as i64;
+            pwm.set_pulse_width(Duration::from_micros(us as u64))
+                .map_err(to_500)?;
+            time::sleep(PERIOD).await;
+        }
+
+        Ok(())
+    }
+
+    /// Sweeps if `sweep_ms` is given, jumps instantly otherwise.
+    async fn move_to(
+        from: f64,
+        to: f64,
+        sweep_ms: Option<u64>,
+        pwm: &Pwm,
+    ) -> Result<(), (Status, String)> {
+        match sweep_ms {
+            Some(sweep_ms) => sweep(from, to, sweep_ms, pwm).await,
+            None => set(to, pwm),
+        }
+    }
+
     pub async fn set_value(servo_value: f64, pwm: &Pwm) -> Result<(), (Status, String)> {
         enable(pwm)?;
 
@@ -195,10 +476,22 @@ pub mod servo {
     ) -> Result<(), (Status, String)> {
         enable(pwm)?;
 
-        set(settings.servo_value, pwm)?;
+        move_to(
+            config.idle_servo_value,
+            settings.servo_value,
+            settings.sweep_ms,
+            pwm,
+        )
+        .await?;
         time::sleep(Duration::from_millis(settings.delay)).await;
 
-        set(config.idle_servo_value, pwm)?;
+        move_to(
+            settings.servo_value,
+            config.idle_servo_value,
+            settings.sweep_ms,
+            pwm,
+        )
+        .await?;
         time::sleep(Duration::from_millis(500)).await;
 
         disable(pwm)
@@ -207,44 +500,82 @@ pub mod servo {
 
 pub mod api {
     use crate::{
-        config::{self, FlipSettings, LightState, ScheduledFlip},
+        config::{
+            self, ApiKeyScope, ConfigState, FlipSettings, HealthState, LightState, ScheduleRequest,
+        },
         servo,
     };
+    use chrono::{DateTime, Utc};
     use rocket::{
         form::Form,
         http::Status,
         request::{FromRequest, Outcome, Request},
-        serde::json::Json,
+        serde::{json::Json, Serialize},
         State,
     };
     use rppal::pwm::Pwm;
     use std::sync::Arc;
 
-    pub struct ApiKey(String);
+    /// Any non-expired key, read-only or full.
+    pub struct ApiKey(pub ApiKeyScope);
 
     #[rocket::async_trait]
     impl<'r> FromRequest<'r> for ApiKey {
         type Error = String;
 
         async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-            async fn is_valid(key: &str) -> Result<bool, (Status, String)> {
-                let config = config::read_config_file().await?;
-                Ok(key == config.api_key)
-            }
-
-            match request.headers().get_one("x-api-key") {
-                None => Outcome::Failure((Status::BadRequest, "missing x-api-key".to_string())),
-                Some(key)
-                    if match is_valid(key).await {
-                        Ok(b) => b,
-                        Err(e) => return Outcome::Failure(e),
-                    } =>
-                {
-                    Outcome::Success(ApiKey(key.to_string()))
+            let config_state = match request.guard::<&State<ConfigState>>().await {
+                Outcome::Success(config_state) => config_state,
+                _ => {
+                    return Outcome::Failure((
+                        Status::InternalServerError,
+                        "missing config state".to_string(),
+                    ))
                 }
-                Some(_) => {
-                    Outcome::Failure((Status::UnprocessableEntity, "x-api-key invalid".to_string()))
+            };
+
+            let scope = match request.headers().get_one("x-api-key") {
+                None => {
+                    return Outcome::Failure((
+                        Status::BadRequest,
+                        "missing x-api-key".to_string(),
+                    ))
                 }
+                Some(key) => config_state
+                    .read()
+                    .await
+                    .api_keys
+                    .iter()
+                    .find(|entry| entry.secret == key && !entry.is_expired())
+                    .map(|entry| entry.scope),
+            };
+
+            match scope {
+                Some(scope) => Outcome::Success(ApiKey(scope)),
+                None => Outcome::Failure((
+                    Status::UnprocessableEntity,
+                    "x-api-key invalid".to_string(),
+                )),
+            }
+        }
+    }
+
+    /// Like [`ApiKey`], but additionally requires `Full` scope.
+    pub struct FullApiKey;
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for FullApiKey {
+        type Error = String;
+
+        async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+            match ApiKey::from_request(request).await {
+                Outcome::Success(ApiKey(ApiKeyScope::Full)) => Outcome::Success(FullApiKey),
+                Outcome::Success(ApiKey(ApiKeyScope::ReadOnly)) => Outcome::Failure((
+                    Status::Forbidden,
+                    "read-only api key cannot perform this action".to_string(),
+                )),
+                Outcome::Failure(e) => Outcome::Failure(e),
+                Outcome::Forward(f) => Outcome::Forward(f),
             }
         }
     }
@@ -258,18 +589,113 @@ pub mod api {
         }
 
         #[get("/settings/on")]
-        pub async fn settings_on() -> Result<Json<FlipSettings>, (Status, String)> {
-            Ok(Json(config::read_config_file().await?.on_settings))
+        pub async fn settings_on(
+            _key: ApiKey,
+            config_state: &State<ConfigState>,
+        ) -> Json<FlipSettings> {
+            Json(config_state.read().await.on_settings.clone())
         }
 
         #[get("/settings/off")]
-        pub async fn settings_off() -> Result<Json<FlipSettings>, (Status, String)> {
-            Ok(Json(config::read_config_file().await?.off_settings))
+        pub async fn settings_off(
+            _key: ApiKey,
+            config_state: &State<ConfigState>,
+        ) -> Json<FlipSettings> {
+            Json(config_state.read().await.off_settings.clone())
         }
 
         #[get("/settings/idle")]
-        pub async fn settings_idle() -> Result<Json<f64>, (Status, String)> {
-            Ok(Json(config::read_config_file().await?.idle_servo_value))
+        pub async fn settings_idle(_key: ApiKey, config_state: &State<ConfigState>) -> Json<f64> {
+            Json(config_state.read().await.idle_servo_value)
+        }
+
+        #[derive(Serialize)]
+        pub struct ApiKeyMetadata {
+            pub label: Option<String>,
+            pub scope: ApiKeyScope,
+            #[serde(with = "chrono::serde::ts_seconds_option")]
+            pub not_after: Option<DateTime<Utc>>,
+        }
+
+        #[get("/keys")]
+        pub async fn keys(
+            _key: ApiKey,
+            config_state: &State<ConfigState>,
+        ) -> Json<Vec<ApiKeyMetadata>> {
+            Json(
+                config_state
+                    .read()
+                    .await
+                    .api_keys
+                    .iter()
+                    .map(|entry| ApiKeyMetadata {
+                        label: entry.label.clone(),
+                        scope: entry.scope,
+                        not_after: entry.not_after,
+                    })
+                    .collect(),
+            )
+        }
+
+        #[derive(Serialize)]
+        pub struct Health {
+            #[serde(with = "chrono::serde::ts_seconds_option")]
+            pub last_success: Option<DateTime<Utc>>,
+            pub pending_schedules: usize,
+            pub consecutive_failures: usize,
+            pub last_error: Option<String>,
+        }
+
+        #[get("/health")]
+        pub async fn health(
+            _key: ApiKey,
+            config_state: &State<ConfigState>,
+            health_state: &State<HealthState>,
+        ) -> Json<Health> {
+            let pending_schedules = config_state.read().await.schedules.len();
+            let health = health_state.read().await;
+            Json(Health {
+                last_success: health.last_success,
+                pending_schedules,
+                consecutive_failures: health.consecutive_failures,
+                last_error: health.last_error.clone(),
+            })
+        }
+    }
+
+    pub mod post {
+        use super::*;
+
+        #[derive(Serialize)]
+        pub struct MintedApiKey {
+            pub secret: String,
+            pub label: Option<String>,
+            pub scope: ApiKeyScope,
+        }
+
+        #[post("/keys", data = "<request>")]
+        pub async fn mint_key(
+            request: Form<config::MintApiKeyRequest>,
+            _key: FullApiKey,
+            config_state: &State<ConfigState>,
+        ) -> Result<Json<MintedApiKey>, (Status, String)> {
+            let request = request.into_inner();
+            let secret = config::generate_api_key();
+
+            let mut config = config_state.write().await;
+            config.api_keys.push(config::ApiKeyEntry {
+                secret: secret.clone(),
+                label: request.label.clone(),
+                scope: request.scope,
+                not_after: request.not_after.map(|timestamp| timestamp.0),
+            });
+            config::write_config_file(&config).await?;
+
+            Ok(Json(MintedApiKey {
+                secret,
+                label: request.label,
+                scope: request.scope,
+            }))
         }
     }
 
@@ -279,28 +705,32 @@ pub mod api {
         #[patch("/light-state", data = "<state>")]
         pub async fn light_state(
             state: Form<LightState>,
-            _key: ApiKey,
+            _key: FullApiKey,
             pwm: &State<Arc<Pwm>>,
+            config_state: &State<ConfigState>,
         ) -> Result<(), (Status, String)> {
-            let config = config::read_config_file().await?;
+            let config = config_state.read().await.clone();
             servo::flip(state.get_settings(&config), &config, pwm).await
         }
 
         #[patch("/settings/test", data = "<settings>")]
         pub async fn settings_test(
             settings: Form<FlipSettings>,
-            _key: ApiKey,
+            _key: FullApiKey,
             pwm: &State<Arc<Pwm>>,
+            config_state: &State<ConfigState>,
         ) -> Result<(), (Status, String)> {
-            servo::flip(&settings, &config::read_config_file().await?, pwm).await
+            let config = config_state.read().await.clone();
+            servo::flip(&settings, &config, pwm).await
         }
 
         #[patch("/settings/on", data = "<settings>")]
         pub async fn settings_on(
             settings: Form<FlipSettings>,
-            _key: ApiKey,
+            _key: FullApiKey,
+            config_state: &State<ConfigState>,
         ) -> Result<(), (Status, String)> {
-            let mut config = config::read_config_file().await?;
+            let mut config = config_state.write().await;
             config.on_settings = settings.into_inner();
             config::write_config_file(&config).await
         }
@@ -308,9 +738,10 @@ pub mod api {
         #[patch("/settings/off", data = "<settings>")]
         pub async fn settings_off(
             settings: Form<FlipSettings>,
-            _key: ApiKey,
+            _key: FullApiKey,
+            config_state: &State<ConfigState>,
         ) -> Result<(), (Status, String)> {
-            let mut config = config::read_config_file().await?;
+            let mut config = config_state.write().await;
             config.off_settings = settings.into_inner();
             config::write_config_file(&config).await
         }
@@ -318,22 +749,35 @@ pub mod api {
         #[patch("/settings/idle", data = "<value>")]
         pub async fn settings_idle(
             value: Form<f64>,
-            _key: ApiKey,
+            _key: FullApiKey,
             pwm: &State<Arc<Pwm>>,
+            config_state: &State<ConfigState>,
         ) -> Result<(), (Status, String)> {
-            let mut config = config::read_config_file().await?;
-            config.idle_servo_value = *value;
             servo::set_value(*value, pwm).await?;
+
+            let mut config = config_state.write().await;
+            config.idle_servo_value = *value;
             config::write_config_file(&config).await
         }
 
-        #[patch("/schedule", data = "<scheduled_flip>")]
+        #[patch("/schedule", data = "<request>")]
         pub async fn schedule(
-            scheduled_flip: Form<ScheduledFlip>,
-            _key: ApiKey,
+            request: Form<ScheduleRequest>,
+            _key: FullApiKey,
+            config_state: &State<ConfigState>,
         ) -> Result<(), (Status, String)> {
-            let mut config = config::read_config_file().await?;
-            config.scheduled_flip = Some(scheduled_flip.into_inner());
+            let request = request.into_inner();
+            let id = request.id.unwrap_or_else(config::generate_schedule_id);
+            let next_fire = request.recurrence.initial_fire();
+
+            let mut config = config_state.write().await;
+            config.schedules.retain(|schedule| schedule.id != id);
+            config.schedules.push(config::Schedule {
+                id,
+                state: request.state,
+                recurrence: request.recurrence,
+                next_fire,
+            });
             config::write_config_file(&config).await
         }
     }
@@ -342,43 +786,224 @@ pub mod api {
         use super::*;
 
         #[delete("/schedule")]
-        pub async fn schedule(_key: ApiKey) -> Result<(), (Status, String)> {
-            let mut config = config::read_config_file().await?;
-            config.scheduled_flip = None;
+        pub async fn schedule(
+            _key: FullApiKey,
+            config_state: &State<ConfigState>,
+        ) -> Result<(), (Status, String)> {
+            let mut config = config_state.write().await;
+            config.schedules.clear();
+            config::write_config_file(&config).await
+        }
+
+        #[delete("/schedule/<id>")]
+        pub async fn schedule_one(
+            id: String,
+            _key: FullApiKey,
+            config_state: &State<ConfigState>,
+        ) -> Result<(), (Status, String)> {
+            let mut config = config_state.write().await;
+            config.schedules.retain(|schedule| schedule.id != id);
+            config::write_config_file(&config).await
+        }
+
+        #[delete("/keys/<label>")]
+        pub async fn key(
+            label: String,
+            _key: FullApiKey,
+            config_state: &State<ConfigState>,
+        ) -> Result<(), (Status, String)> {
+            let mut config = config_state.write().await;
+
+            let remaining_full_keys = config
+                .api_keys
+                .iter()
+                .filter(|entry| entry.label.as_deref() != Some(label.as_str()))
+                .filter(|entry| entry.scope == ApiKeyScope::Full && !entry.is_expired())
+                .count();
+            if remaining_full_keys == 0 {
+                return Err((
+                    Status::Conflict,
+                    "cannot revoke the last full-scope api key".to_string(),
+                ));
+            }
+
+            config
+                .api_keys
+                .retain(|entry| entry.label.as_deref() != Some(label.as_str()));
             config::write_config_file(&config).await
         }
     }
 }
 
-async fn run_schedules(pwm: &Pwm) {
-    if let Ok(mut config) = config::read_config_file().await {
-        if let Some(scheduled_flip) = config.scheduled_flip {
-            if scheduled_flip.datetime.inner <= Utc::now() {
-                config.scheduled_flip = None;
-                config::write_config_file(&config).await.ok();
-                servo::flip(scheduled_flip.state.get_settings(&config), &config, pwm)
-                    .await
-                    .ok();
+enum FlipOutcome {
+    Succeeded,
+    GaveUp,
+    ShuttingDown,
+}
+
+/// Retries with its own local attempt counter, independent of any other
+/// concurrent or prior call — `HealthStatus.consecutive_failures` is updated
+/// purely for `/health` reporting, not used to gate giving up. The wait
+/// between retries races the shutdown signal so an unbounded
+/// `max_errors_in_row: None` can't block graceful shutdown.
+async fn fire_schedule_with_retry(
+    pwm: &Pwm,
+    config: &config::Config,
+    schedule: &config::Schedule,
+    health_state: &config::HealthState,
+    shutdown: &mut Shutdown,
+) -> FlipOutcome {
+    let max_errors_in_row = config.max_errors_in_row.unwrap_or(usize::MAX);
+    let mut delay =
+        Duration::from_millis(config.retry_base_delay_ms.max(1)).min(Duration::from_secs(60));
+    let mut attempt = 0usize;
+
+    loop {
+        match servo::flip(schedule.state.get_settings(config), config, pwm).await {
+            Ok(()) => {
+                let mut health = health_state.write().await;
+                health.last_success = Some(Utc::now());
+                health.consecutive_failures = 0;
+                health.last_error = None;
+                return FlipOutcome::Succeeded;
+            }
+            Err((_, message)) => {
+                attempt += 1;
+
+                let mut health = health_state.write().await;
+                health.consecutive_failures = attempt;
+                health.last_error = Some(message);
+                drop(health);
+
+                if attempt >= max_errors_in_row {
+                    return FlipOutcome::GaveUp;
+                }
+
+                tokio::select! {
+                    _ = time::sleep(delay) => {}
+                    _ = &mut *shutdown => return FlipOutcome::ShuttingDown,
+                }
+                delay = (delay * 2).min(Duration::from_secs(60));
             }
         }
     }
 }
 
+/// A schedule that exhausts its retries keeps its `next_fire` in the past,
+/// so the next tick retries it rather than skipping it.
+async fn run_schedules(
+    pwm: &Pwm,
+    config_state: &config::ConfigState,
+    health_state: &config::HealthState,
+    mut shutdown: Shutdown,
+) {
+    let now = Utc::now();
+    let due: Vec<config::Schedule> = config_state
+        .read()
+        .await
+        .schedules
+        .iter()
+        .filter(|schedule| schedule.next_fire <= now)
+        .cloned()
+        .collect();
+
+    for schedule in due {
+        let config = config_state.read().await.clone();
+        let outcome =
+            fire_schedule_with_retry(pwm, &config, &schedule, health_state, &mut shutdown).await;
+        match outcome {
+            FlipOutcome::ShuttingDown => break,
+            FlipOutcome::GaveUp => continue,
+            FlipOutcome::Succeeded => {}
+        }
+
+        let mut config = config_state.write().await;
+        let Some(index) = config.schedules.iter().position(|s| s.id == schedule.id) else {
+            continue;
+        };
+        match schedule.recurrence.advance(schedule.next_fire) {
+            Some(next_fire) => config.schedules[index].next_fire = next_fire,
+            None => {
+                config.schedules.remove(index);
+            }
+        }
+        config::write_config_file(&config).await.ok();
+    }
+}
+
+/// Runs the background schedule loop and, on shutdown, stops that loop and
+/// parks the servo so it can't be left energized or mid-flip on teardown.
+struct ScheduleFairing {
+    pwm: Arc<Pwm>,
+    task: AsyncMutex<Option<JoinHandle<()>>>,
+}
+
+#[rocket::async_trait]
+impl Fairing for ScheduleFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "schedule runner",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let mut shutdown = rocket.shutdown();
+        let pwm = self.pwm.clone();
+        let config_state = rocket
+            .state::<config::ConfigState>()
+            .expect("config state is managed before this fairing is attached")
+            .clone();
+        let health_state = rocket
+            .state::<config::HealthState>()
+            .expect("health state is managed before this fairing is attached")
+            .clone();
+
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = time::sleep(Duration::from_secs(60)) => {
+                        run_schedules(&pwm, &config_state, &health_state, shutdown.clone()).await
+                    }
+                    _ = &mut shutdown => break,
+                }
+            }
+        });
+
+        *self.task.lock().await = Some(task);
+    }
+
+    async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+        // Let the schedule loop notice shutdown and finish any flip in
+        // progress before we park the servo, so the two can't race.
+        if let Some(task) = self.task.lock().await.take() {
+            task.await.ok();
+        }
+
+        if let Some(config_state) = rocket.state::<config::ConfigState>() {
+            let idle_servo_value = config_state.read().await.idle_servo_value;
+            servo::set_value(idle_servo_value, &self.pwm).await.ok();
+        }
+    }
+}
+
 #[launch]
 async fn rocket() -> _ {
     let config = config::read_config_file().await.unwrap(); // panic if the config file is badly formatted before we start rocket
 
     let pwm = Arc::new(servo::create_pwm(&config).unwrap());
-
-    let pwm2 = pwm.clone();
-    tokio::spawn(async move {
-        loop {
-            time::sleep(Duration::from_secs(60)).await;
-            run_schedules(&pwm2).await;
-        }
-    });
+    let shutdown_grace_secs = config.shutdown_grace_secs;
+    let config_state: config::ConfigState = Arc::new(RwLock::new(config));
+    let health_state: config::HealthState = Arc::new(RwLock::new(config::HealthStatus::default()));
 
     rocket::build()
+        .configure(rocket::Config {
+            shutdown: rocket::config::Shutdown {
+                grace: shutdown_grace_secs,
+                ..rocket::config::Shutdown::default()
+            },
+            ..rocket::Config::default()
+        })
         .mount("/", routes![api::get::index])
         .mount(
             "/api/v0/",
@@ -386,6 +1011,9 @@ async fn rocket() -> _ {
                 api::get::settings_on,
                 api::get::settings_off,
                 api::get::settings_idle,
+                api::get::keys,
+                api::get::health,
+                api::post::mint_key,
                 api::patch::light_state,
                 api::patch::settings_test,
                 api::patch::settings_on,
@@ -393,7 +1021,15 @@ async fn rocket() -> _ {
                 api::patch::settings_idle,
                 api::patch::schedule,
                 api::delete::schedule,
+                api::delete::schedule_one,
+                api::delete::key,
             ],
         )
+        .attach(ScheduleFairing {
+            pwm: pwm.clone(),
+            task: AsyncMutex::new(None),
+        })
         .manage(pwm)
+        .manage(config_state)
+        .manage(health_state)
 }